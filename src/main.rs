@@ -1,13 +1,22 @@
 use anyhow::{bail, Error, Result};
-use log::{error, info};
+use async_trait::async_trait;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
 use std::{
     cmp::min,
+    collections::VecDeque,
     future::Future,
     path::PathBuf,
     str::FromStr,
     time::{Duration, Instant},
 };
-use tokio::{fs, time};
+use tokio::{
+    fs,
+    io::{unix::AsyncFd, Interest},
+    net::{UnixListener, UnixStream},
+    sync::{mpsc, watch},
+    time,
+};
 
 async fn read<T>(path: &PathBuf) -> Result<std::result::Result<T, <T as FromStr>::Err>>
 where
@@ -16,7 +25,7 @@ where
     Ok(fs::read_to_string(path).await?.trim().parse::<T>())
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Deserialize)]
 enum Model {
     PinePhone,
     PinePhonePro,
@@ -33,39 +42,38 @@ impl Model {
         }
     }
 
-    // valid values that can be written to input_current_limit
-    fn valid_limits(&self) -> &'static [u32] {
-        static PPP: [u32; 6] = [450000, 850000, 1000000, 1250000, 1500000, 2000000];
-        static PP: [u32; 4] = [500000, 900000, 1500000, 2000000];
+    fn table<'c>(&self, cfg: &'c Config) -> &'c ModelTable {
         match self {
-            Model::PinePhonePro => &PPP,
-            Model::PinePhone => &PP,
+            Model::PinePhonePro => &cfg.pinephone_pro,
+            Model::PinePhone => &cfg.pinephone,
         }
     }
 
+    // valid values that can be written to input_current_limit
+    fn valid_limits<'c>(&self, cfg: &'c Config) -> &'c [u32] {
+        &self.table(cfg).valid_limits
+    }
+
     // return the default input current limit
-    fn default_limit(&self) -> u32 {
-        match self {
-            Model::PinePhonePro => self.valid_limits()[0],
-            Model::PinePhone => self.valid_limits()[0],
-        }
+    fn default_limit(&self, cfg: &Config) -> u32 {
+        let t = self.table(cfg);
+        t.valid_limits[t.default_idx]
     }
 
     // return the max input current limit
-    fn max_limit(&self) -> u32 {
-        match self {
-            Model::PinePhonePro => self.valid_limits()[5],
-            Model::PinePhone => self.valid_limits()[3],
-        }
+    fn max_limit(&self, cfg: &Config) -> u32 {
+        let t = self.table(cfg);
+        t.valid_limits[t.max_idx]
     }
 
-    fn min_limit(&self) -> u32 {
-        self.valid_limits()[0]
+    fn min_limit(&self, cfg: &Config) -> u32 {
+        let t = self.table(cfg);
+        t.valid_limits[t.min_idx]
     }
 
     // given the current input_curent_limit, step one increment up or down and return the new value
-    fn limit_step(&self, up: bool, cur: u32) -> u32 {
-        let valid = self.valid_limits();
+    fn limit_step(&self, cfg: &Config, up: bool, cur: u32) -> u32 {
+        let valid = self.valid_limits(cfg);
         for (i, v) in valid.iter().enumerate() {
             if *v == cur {
                 if up {
@@ -77,8 +85,171 @@ impl Model {
                 }
             }
         }
-        valid[2]
+        valid[min(2, valid.len() - 1)]
+    }
+}
+
+// the valid input_current_limit steps for one phone model, and which
+// of them to treat as the default/max/min
+#[derive(Debug, Clone, Deserialize)]
+struct ModelTable {
+    valid_limits: Vec<u32>,
+    default_idx: usize,
+    max_idx: usize,
+    min_idx: usize,
+}
+
+impl ModelTable {
+    fn validate(&self, name: &str) -> Result<()> {
+        if self.valid_limits.windows(2).any(|w| w[0] >= w[1]) {
+            bail!("{}: valid_limits must be sorted ascending", name);
+        }
+        for (idx, field) in [
+            (self.default_idx, "default_idx"),
+            (self.max_idx, "max_idx"),
+            (self.min_idx, "min_idx"),
+        ] {
+            if idx >= self.valid_limits.len() {
+                bail!("{}: {} out of range", name, field);
+            }
+        }
+        Ok(())
+    }
+}
+
+// thresholds and per-model current tables that used to be hardcoded
+// constants, now loaded from a TOML config file (falling back to the
+// built-in defaults below when none is present) so users on
+// non-standard kernels or keyboards can tune behavior without
+// recompiling
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+struct Config {
+    // max combined keyboard+phone input current, uA
+    kb_limit: i32,
+    // voltage difference, in uV, above which we prefer charging the
+    // battery that's lower
+    voltage_diff: u32,
+    // below this main battery soc%, prefer charging it even from the
+    // keyboard battery
+    low_soc_threshold: u32,
+    // minimum time between limit step changes, seconds
+    step_secs: u64,
+    // how long the boost converter can be left offline before we force
+    // it back online to avoid losing communication with it, seconds
+    offline_secs: u64,
+    // main battery charge ceiling (soc%), if any; None charges to full
+    charge_ceiling: Option<u32>,
+    pinephone: ModelTable,
+    pinephone_pro: ModelTable,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            kb_limit: 2300000,
+            voltage_diff: 150000,
+            low_soc_threshold: 30,
+            step_secs: 10,
+            offline_secs: 20,
+            charge_ceiling: None,
+            pinephone: ModelTable {
+                valid_limits: vec![500000, 900000, 1500000, 2000000],
+                default_idx: 0,
+                max_idx: 3,
+                min_idx: 0,
+            },
+            pinephone_pro: ModelTable {
+                valid_limits: vec![450000, 850000, 1000000, 1250000, 1500000, 2000000],
+                default_idx: 0,
+                max_idx: 5,
+                min_idx: 0,
+            },
+        }
+    }
+}
+
+impl Config {
+    fn validate(&self) -> Result<()> {
+        self.pinephone.validate("pinephone")?;
+        self.pinephone_pro.validate("pinephone_pro")?;
+        Ok(())
+    }
+
+    // load from `path` if it exists, otherwise fall back to the
+    // built-in defaults
+    async fn load(path: &PathBuf) -> Result<Config> {
+        let cfg = if path.exists() {
+            let data = fs::read_to_string(path).await?;
+            toml::from_str(&data)?
+        } else {
+            Config::default()
+        };
+        cfg.validate()?;
+        Ok(cfg)
+    }
+}
+
+// the live state read from a keyboard/main battery pair and the
+// controls used to influence charging behavior, abstracted so the
+// control loop in `Ctx` can run equally well against real sysfs nodes
+// or a recorded scenario (see `ReplaySource`)
+#[async_trait]
+trait BatterySource {
+    fn model(&self) -> Model;
+    async fn info(&self) -> Result<Info>;
+
+    // sysfs nodes worth watching for plug/unplug and charge state
+    // changes, so the control loop can react immediately instead of
+    // polling. Sources with nothing to watch (e.g. `ReplaySource`)
+    // just return an empty list.
+    fn watch_paths(&self) -> Vec<PathBuf> {
+        Vec::new()
+    }
+
+    // the clock the soc trend fit in `Ctx` runs against. Real devices
+    // just use the wall clock, but `ReplaySource` ticks this forward
+    // by each row's recorded timestamp so a scenario's trend estimate
+    // reproduces regardless of how long replay actually takes to run.
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+#[async_trait]
+trait BatterySink: BatterySource {
+    async fn set_online(&self, desired: bool, cur: bool) -> Result<()>;
+    async fn set_limit(&self, limit: u32) -> Result<()>;
+    async fn set_kb_limit(&self, limit: u32) -> Result<()>;
+
+    async fn set_limit_step(&self, cfg: &Config, up: bool, cur: u32) -> Result<()> {
+        let limit = self.model().limit_step(cfg, up, cur);
+        if limit != cur {
+            self.set_limit(limit).await?;
+        }
+        Ok(())
+    }
+
+    async fn set_limit_default(&self, cfg: &Config, cur: u32) -> Result<()> {
+        let def = self.model().default_limit(cfg);
+        if cur != def {
+            self.set_limit(def).await?;
+        }
+        Ok(())
+    }
+
+    async fn set_limit_max(&self, cfg: &Config, cur: u32) -> Result<()> {
+        let def = self.model().max_limit(cfg);
+        if cur != def {
+            self.set_limit(def).await?;
+        }
+        Ok(())
     }
+
+    // observe the Action `Ctx::step` decided on for this tick. Real
+    // devices have nothing to do with it; `ReplaySource` records it
+    // so scenario-driven tests can assert the decision sequence.
+    async fn record_action(&self, _action: Action) {}
 }
 
 struct Device {
@@ -131,6 +302,32 @@ impl Device {
         }
     }
 
+}
+
+#[async_trait]
+impl BatterySource for Device {
+    fn model(&self) -> Model {
+        self.model
+    }
+
+    async fn info(&self) -> Result<Info> {
+        Ok(Info {
+            kbd: KeyboardBattery::get(self).await?,
+            mb: MainBattery::get(self).await?,
+        })
+    }
+
+    fn watch_paths(&self) -> Vec<PathBuf> {
+        vec![
+            self.kb_state.clone(),
+            self.kb_enabled.clone(),
+            self.mb_state.clone(),
+        ]
+    }
+}
+
+#[async_trait]
+impl BatterySink for Device {
     async fn set_online(&self, desired: bool, cur: bool) -> Result<()> {
         if desired != cur {
             info!("setting online: {}", desired);
@@ -150,37 +347,9 @@ impl Device {
         info!("setting kb input_current_limit: {}", limit / 1000);
         Ok(fs::write(&self.kb_limit, &format!("{}\n", limit)).await?)
     }
-
-    async fn set_limit_step(&self, up: bool, cur: u32) -> Result<()> {
-        let limit = self.model.limit_step(up, cur);
-        Ok(if limit != cur {
-            self.set_limit(limit).await?
-        })
-    }
-
-    async fn set_limit_default(&self, cur: u32) -> Result<()> {
-        let def = self.model.default_limit();
-        Ok(if cur != def {
-            self.set_limit(def).await?
-        })
-    }
-
-    async fn set_limit_max(&self, cur: u32) -> Result<()> {
-        let def = self.model.max_limit();
-        Ok(if cur != def {
-            self.set_limit(def).await?
-        })
-    }
-
-    async fn info(&self) -> Result<Info> {
-        Ok(Info {
-            kbd: KeyboardBattery::get(self).await?,
-            mb: MainBattery::get(self).await?,
-        })
-    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 enum State {
     Charging,
     Discharging,
@@ -200,7 +369,7 @@ impl FromStr for State {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize)]
 struct KeyboardBattery {
     state: State,
     soc: Option<u32>,
@@ -223,7 +392,7 @@ impl KeyboardBattery {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize)]
 struct MainBattery {
     state: State,
     soc: u32,
@@ -276,13 +445,13 @@ impl MainBattery {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize)]
 struct Info {
     kbd: KeyboardBattery,
     mb: MainBattery,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
 enum Action {
     MaybeStepUp,
     MaybeStepDown,
@@ -293,34 +462,319 @@ enum Action {
     Pass,
 }
 
-struct Ctx {
-    dev: Device,
+#[derive(Debug, Clone, Serialize)]
+struct Status {
+    info: Info,
+    action: Action,
+    // estimated seconds to empty (discharging) or full (charging) for
+    // the main battery, based on the recent soc trend
+    remaining_secs: Option<u64>,
+}
+
+// one sampled row of a recorded scenario, as fed to `decide` via
+// `ReplaySource`
+#[derive(Debug, Clone, Deserialize)]
+struct ReplayRow {
+    // seconds since the start of the scenario this row was recorded
+    // at, used to drive the soc trend fit at replay speed rather than
+    // wall-clock speed
+    timestamp_secs: u64,
+    kb_state: State,
+    kb_soc: Option<u32>,
+    kb_voltage: u32,
+    kb_current: i32,
+    kb_limit: u32,
+    kb_enabled: bool,
+    mb_state: State,
+    mb_soc: u32,
+    mb_voltage: u32,
+    mb_current: i32,
+    mb_limit: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct Scenario {
+    model: Model,
+    rows: Vec<ReplayRow>,
+}
+
+// drives `Ctx::step` from a recorded scenario instead of real sysfs
+// nodes, so the decision logic can be exercised and regression-tested
+// off-device. Writes are not applied anywhere, just logged, since the
+// recorded rows don't react to them.
+struct ReplaySource {
+    model: Model,
+    rows: Vec<ReplayRow>,
+    idx: std::sync::atomic::AtomicUsize,
+    // base instant `now()` ticks forward from by the most recently
+    // read row's timestamp, so the soc trend fit sees scenario time
+    // rather than however long replay actually took to run
+    base: Instant,
+    last_ts: std::sync::atomic::AtomicU64,
+    // the Action `Ctx::step` decided on for each row, in order, so
+    // tests can assert a scenario's full decision sequence
+    actions: std::sync::Mutex<Vec<Action>>,
+}
+
+impl ReplaySource {
+    async fn load(path: &PathBuf) -> Result<ReplaySource> {
+        let data = fs::read_to_string(path).await?;
+        let scenario: Scenario = serde_json::from_str(&data)?;
+        Ok(ReplaySource::new(scenario.model, scenario.rows))
+    }
+
+    fn new(model: Model, rows: Vec<ReplayRow>) -> ReplaySource {
+        ReplaySource {
+            model,
+            rows,
+            idx: std::sync::atomic::AtomicUsize::new(0),
+            base: Instant::now(),
+            last_ts: std::sync::atomic::AtomicU64::new(0),
+            actions: std::sync::Mutex::new(Vec::new()),
+        }
+    }
+
+    // the Actions chosen so far, in row order
+    #[cfg(test)]
+    fn actions(&self) -> Vec<Action> {
+        self.actions.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl BatterySource for ReplaySource {
+    fn model(&self) -> Model {
+        self.model
+    }
+
+    async fn info(&self) -> Result<Info> {
+        let i = self.idx.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let row = self
+            .rows
+            .get(i)
+            .ok_or_else(|| Error::msg("replay scenario exhausted"))?;
+        self.last_ts
+            .store(row.timestamp_secs, std::sync::atomic::Ordering::Relaxed);
+        Ok(Info {
+            kbd: KeyboardBattery {
+                state: row.kb_state,
+                soc: row.kb_soc,
+                voltage: row.kb_voltage,
+                current: row.kb_current,
+                limit: row.kb_limit,
+                enabled: row.kb_enabled,
+            },
+            mb: MainBattery {
+                state: row.mb_state,
+                soc: row.mb_soc,
+                voltage: row.mb_voltage,
+                current: row.mb_current,
+                limit: row.mb_limit,
+            },
+        })
+    }
+
+    fn now(&self) -> Instant {
+        self.base
+            + Duration::from_secs(self.last_ts.load(std::sync::atomic::Ordering::Relaxed))
+    }
+}
+
+#[async_trait]
+impl BatterySink for ReplaySource {
+    async fn set_online(&self, desired: bool, _cur: bool) -> Result<()> {
+        info!("replay: would set online: {}", desired);
+        Ok(())
+    }
+
+    async fn set_limit(&self, limit: u32) -> Result<()> {
+        info!("replay: would set input_current_limit: {}", limit / 1000);
+        Ok(())
+    }
+
+    async fn set_kb_limit(&self, limit: u32) -> Result<()> {
+        info!("replay: would set kb input_current_limit: {}", limit / 1000);
+        Ok(())
+    }
+
+    async fn record_action(&self, action: Action) {
+        self.actions.lock().unwrap().push(action);
+    }
+}
+
+// default path for the status ipc socket, overridable with the
+// KBPWRD_SOCKET environment variable
+const DEFAULT_SOCKET_PATH: &str = "/run/pinephone-kbpwrd.sock";
+
+async fn handle_status_client(mut sock: UnixStream, rx: watch::Receiver<Option<Status>>) {
+    use tokio::io::AsyncWriteExt;
+    let status = rx.borrow().clone();
+    let res = match status {
+        Some(status) => serde_json::to_vec(&status),
+        None => serde_json::to_vec(&serde_json::json!({})),
+    };
+    match res {
+        Ok(mut buf) => {
+            buf.push(b'\n');
+            if let Err(e) = sock.write_all(&buf).await {
+                warn!("failed writing status to client: {}", e);
+            }
+        }
+        Err(e) => warn!("failed serializing status: {}", e),
+    }
+}
+
+// listen on a unix socket and write the latest Info/Action as json to
+// anyone who connects, so status bars (i3status, waybar, ...) can read
+// the daemon's state without scraping sysfs themselves
+async fn run_status_server(path: PathBuf, rx: watch::Receiver<Option<Status>>) -> Result<()> {
+    let _ = fs::remove_file(&path).await;
+    let listener = UnixListener::bind(&path)?;
+    info!("status socket listening on {}", path.display());
+    loop {
+        match listener.accept().await {
+            // spawn each client on its own task so a stalled reader
+            // can't hold up new connections
+            Ok((sock, _addr)) => {
+                tokio::task::spawn(handle_status_client(sock, rx.clone()));
+            }
+            Err(e) => warn!("status socket accept failed: {}", e),
+        }
+    }
+}
+
+struct Ctx<B: BatterySink> {
+    dev: B,
+    config: Config,
     kb_charging: bool,
     last_step: Instant,
     last_offline: Instant,
+    capping: bool,
+    status: watch::Sender<Option<Status>>,
+    soc_samples: VecDeque<(Instant, u32)>,
+    last_mb_state: Option<State>,
 }
 
-const KBLIM: i32 = 2300000;
+// how far below the ceiling the main battery soc must fall before we
+// resume charging, to avoid oscillating right at the boundary
+const CEILING_HYSTERESIS: u32 = 5;
+
+// how long a history of soc samples to keep around for the trend fit
+const SOC_SAMPLE_WINDOW: Duration = Duration::from_secs(600);
+
+// don't bother estimating until we've watched the trend for at least
+// this long, the slope is too noisy on a handful of samples
+const MIN_SAMPLE_WINDOW: Duration = Duration::from_secs(60);
+
+// ignore slopes shallower than this (%/s), treat them as noise rather
+// than a real trend (roughly 1% per 10 minutes)
+const MIN_SOC_SLOPE: f64 = 1.0 / 600.0;
+
+impl<B: BatterySink + Sync> Ctx<B> {
+    fn new(dev: B, config: Config, status: watch::Sender<Option<Status>>) -> Ctx<B> {
+        Ctx {
+            dev,
+            config,
+            kb_charging: false,
+            last_step: Instant::now(),
+            last_offline: Instant::now(),
+            capping: false,
+            status,
+            soc_samples: VecDeque::new(),
+            last_mb_state: None,
+        }
+    }
+
+    // record a (time, soc) sample for the main battery trend fit,
+    // resetting the history whenever the charge/discharge direction
+    // changes so estimates don't bleed across the flip
+    fn sample_soc(&mut self, info: &Info) {
+        if self.last_mb_state != Some(info.mb.state) {
+            self.soc_samples.clear();
+            self.last_mb_state = Some(info.mb.state);
+        }
+        let now = self.dev.now();
+        self.soc_samples.push_back((now, info.mb.soc));
+        while let Some(&(t, _)) = self.soc_samples.front() {
+            if now.duration_since(t) > SOC_SAMPLE_WINDOW {
+                self.soc_samples.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    // estimate time to empty (discharging) or time to full (charging)
+    // by fitting a line to the recent soc samples via least squares
+    // and extrapolating to 0%/100%
+    fn estimate_remaining(&self, info: &Info) -> Option<Duration> {
+        let &(t0, _) = self.soc_samples.front()?;
+        let now = self.dev.now();
+        if now.duration_since(t0) < MIN_SAMPLE_WINDOW {
+            return None;
+        }
+        let n = self.soc_samples.len() as f64;
+        let xs: Vec<f64> = self
+            .soc_samples
+            .iter()
+            .map(|(t, _)| t.duration_since(t0).as_secs_f64())
+            .collect();
+        let ys: Vec<f64> = self.soc_samples.iter().map(|(_, soc)| *soc as f64).collect();
+        let xbar = xs.iter().sum::<f64>() / n;
+        let ybar = ys.iter().sum::<f64>() / n;
+        let den: f64 = xs.iter().map(|x| (x - xbar).powi(2)).sum();
+        if den == 0.0 {
+            return None;
+        }
+        let num: f64 = xs
+            .iter()
+            .zip(ys.iter())
+            .map(|(x, y)| (x - xbar) * (y - ybar))
+            .sum();
+        let slope = num / den;
+        if slope.abs() < MIN_SOC_SLOPE {
+            return None;
+        }
+        let secs = match info.mb.state {
+            State::Discharging if slope < 0.0 => info.mb.soc as f64 / -slope,
+            State::Charging if slope > 0.0 => (100.0 - info.mb.soc as f64) / slope,
+            _ => return None,
+        };
+        Some(Duration::from_secs_f64(secs))
+    }
 
-impl Ctx {
     fn decide(&mut self, info: &Info) -> Action {
+        if let Some(ceiling) = self.config.charge_ceiling {
+            if info.mb.soc >= ceiling {
+                self.capping = true;
+            } else if info.mb.soc < ceiling.saturating_sub(CEILING_HYSTERESIS) {
+                self.capping = false;
+            }
+            if self.capping && matches!(info.mb.state, State::Charging | State::Full) {
+                return Action::MaybeStepDown;
+            }
+        }
         match info.kbd.state {
             State::Charging => {
                 if !self.kb_charging {
                     self.kb_charging = true;
                     Action::SetDefault
                 } else {
-                    let lim = KBLIM + (KBLIM >> 4);
+                    let kb_limit = self.config.kb_limit;
+                    let lim = kb_limit + (kb_limit >> 4);
                     let ka = info.kbd.current;
                     let tot = ka + info.mb.limit as i32;
-                    let nextl = self.dev.model.limit_step(true, info.mb.limit) as i32;
+                    let nextl = self
+                        .dev
+                        .model()
+                        .limit_step(&self.config, true, info.mb.limit) as i32;
                     if ka + nextl < lim && info.mb.current < 0 {
                         Action::MaybeStepUp
                     } else if info.mb.current < 0 {
                         Action::MaybePhUpKbDown
                     } else if tot >= lim {
                         Action::MaybeStepDown
-                    } else if tot < KBLIM {
+                    } else if tot < kb_limit {
                         Action::MaybeStepKbUp
                     } else {
                         Action::Pass
@@ -339,19 +793,20 @@ impl Ctx {
                     self.kb_charging = false;
                     Action::SetDefault
                 } else {
+                    let low_soc = self.config.low_soc_threshold;
                     match info.mb.state {
                         State::Full => Action::SetDefault,
-                        State::Charging if info.mb.soc > 30 => Action::MaybeStepDown,
-                        State::Discharging if info.mb.soc > 30 => {
-                            const VDIF: u32 = 150000;
+                        State::Charging if info.mb.soc > low_soc => Action::MaybeStepDown,
+                        State::Discharging if info.mb.soc > low_soc => {
+                            let vdif = self.config.voltage_diff;
                             let mbv = info.mb.voltage;
                             let kbv = info.kbd.voltage;
                             let mbc = info.mb.current.abs();
                             let kbc = info.kbd.current.abs();
-                            if mbv > kbv && mbv - kbv > VDIF {
+                            if mbv > kbv && mbv - kbv > vdif {
                                 Action::MaybeStepDown
-                            } else if (mbv >= kbv && mbv - kbv < VDIF)
-                                || (kbv >= mbv && kbv - mbv < VDIF)
+                            } else if (mbv >= kbv && mbv - kbv < vdif)
+                                || (kbv >= mbv && kbv - mbv < vdif)
                             {
                                 Action::Pass
                             } else if mbc > kbc {
@@ -360,11 +815,15 @@ impl Ctx {
                                 Action::Pass
                             }
                         }
-                        // keep the main battery above 30% for as long as
-                        // possible even if that means charging it.
+                        // keep the main battery above the low soc
+                        // threshold for as long as possible even if
+                        // that means charging it.
                         State::Charging => {
-                            let delta =
-                                info.mb.limit - self.dev.model.limit_step(false, info.mb.limit);
+                            let delta = info.mb.limit
+                                - self
+                                    .dev
+                                    .model()
+                                    .limit_step(&self.config, false, info.mb.limit);
                             if info.mb.current > 0 && delta < info.mb.current as u32 {
                                 Action::MaybeStepDown
                             } else {
@@ -378,12 +837,12 @@ impl Ctx {
         }
     }
 
-    async fn maybe_step<'a, R: Future<Output = Result<()>>, F: FnOnce(&'a mut Ctx) -> R>(
+    async fn maybe_step<'a, R: Future<Output = Result<()>>, F: FnOnce(&'a mut Ctx<B>) -> R>(
         &'a mut self,
         f: F,
     ) -> Result<()> {
-        const STEP: Duration = Duration::from_secs(10);
-        if self.last_step.elapsed() > STEP {
+        let step = Duration::from_secs(self.config.step_secs);
+        if self.last_step.elapsed() > step {
             self.last_step = Instant::now();
             f(self).await?
         }
@@ -394,27 +853,34 @@ impl Ctx {
         if !info.kbd.enabled {
             self.dev.set_online(true, info.kbd.enabled).await?;
         } else {
-            self.dev.set_limit_step(true, info.mb.limit).await?;
+            self.dev
+                .set_limit_step(&self.config, true, info.mb.limit)
+                .await?;
         }
         Ok(())
     }
 
     async fn step_down(&mut self, info: &Info) -> Result<()> {
-        if info.mb.limit == self.dev.model.min_limit() {
+        if info.mb.limit == self.dev.model().min_limit(&self.config) {
             self.last_offline = Instant::now();
             self.dev.set_online(false, info.kbd.enabled).await?;
         } else {
-            self.dev.set_limit_step(false, info.mb.limit).await?;
+            self.dev
+                .set_limit_step(&self.config, false, info.mb.limit)
+                .await?;
         }
         Ok(())
     }
 
     async fn step(&mut self) -> Result<()> {
-        const OFFLINE: Duration = Duration::from_secs(20);
+        let offline = Duration::from_secs(self.config.offline_secs);
         let info = self.dev.info().await?;
+        self.sample_soc(&info);
+        let estimate = self.estimate_remaining(&info);
         let action = self.decide(&info);
+        self.dev.record_action(action).await;
         info!(
-            "ph v: {}, a: {}, s: {:?}, l: {}, c: {}, kb v: {}, a: {}, s: {:?}, l: {}, c: {}, act: {:?}",
+            "ph v: {}, a: {}, s: {:?}, l: {}, c: {}, kb v: {}, a: {}, s: {:?}, l: {}, c: {}, act: {:?}, eta_min: {}",
             info.mb.voltage / 1000,
             info.mb.current / 1000,
             info.mb.state,
@@ -428,13 +894,18 @@ impl Ctx {
                 Some(v) => v.to_string(),
                 None => "n/a".into(),
             },
-            action
+            action,
+            match estimate {
+                Some(d) => (d.as_secs() / 60).to_string(),
+                None => "n/a".into(),
+            }
         );
         // if the boost is left offline too long we lose communication with it
-        if !info.kbd.enabled && self.last_offline.elapsed() > OFFLINE {
+        if !info.kbd.enabled && self.last_offline.elapsed() > offline {
             self.last_step = Instant::now();
             self.dev.set_online(true, info.kbd.enabled).await?;
         }
+        let kb_limit = self.config.kb_limit as u32;
         match action {
             Action::Pass => (),
             Action::MaybeStepUp => {
@@ -444,9 +915,11 @@ impl Ctx {
             Action::MaybePhUpKbDown => {
                 self.maybe_step(|ctx| async {
                     ctx.dev.set_online(true, info.kbd.enabled).await?;
-                    let lim = ctx.dev.model.limit_step(true, info.mb.limit);
-                    ctx.dev.set_kb_limit(KBLIM as u32 - lim).await?;
-                    ctx.dev.set_limit_step(true, info.mb.limit).await?;
+                    let lim = ctx.dev.model().limit_step(&ctx.config, true, info.mb.limit);
+                    ctx.dev.set_kb_limit(kb_limit - lim).await?;
+                    ctx.dev
+                        .set_limit_step(&ctx.config, true, info.mb.limit)
+                        .await?;
                     Ok(())
                 })
                 .await?
@@ -454,9 +927,9 @@ impl Ctx {
             Action::MaybeStepKbUp => {
                 self.maybe_step(|ctx| async {
                     ctx.dev.set_online(true, info.kbd.enabled).await?;
-                    if info.kbd.limit < KBLIM as u32 {
+                    if info.kbd.limit < kb_limit {
                         ctx.dev
-                            .set_kb_limit(min(info.kbd.limit + 100000, KBLIM as u32))
+                            .set_kb_limit(min(info.kbd.limit + 100000, kb_limit))
                             .await?
                     }
                     Ok(())
@@ -470,37 +943,193 @@ impl Ctx {
             Action::SetDefault => {
                 self.last_step = Instant::now();
                 self.dev.set_online(true, info.kbd.enabled).await?;
-                self.dev.set_limit_default(info.mb.limit).await?;
                 self.dev
-                    .set_kb_limit(KBLIM as u32 - self.dev.model.default_limit())
+                    .set_limit_default(&self.config, info.mb.limit)
+                    .await?;
+                self.dev
+                    .set_kb_limit(kb_limit - self.dev.model().default_limit(&self.config))
                     .await?;
             }
             Action::SetMax => {
                 self.last_step = Instant::now();
                 self.dev.set_online(true, info.kbd.enabled).await?;
-                self.dev.set_limit_max(info.mb.limit).await?;
+                self.dev.set_limit_max(&self.config, info.mb.limit).await?;
                 self.dev
-                    .set_kb_limit(KBLIM as u32 - self.dev.model.default_limit())
+                    .set_kb_limit(kb_limit - self.dev.model().default_limit(&self.config))
                     .await?;
             }
         }
+        // publish the fresh info/action so ipc readers never perturb
+        // the control loop itself
+        self.status.send_replace(Some(Status {
+            info,
+            action,
+            remaining_secs: estimate.map(|d| d.as_secs()),
+        }));
         Ok(())
     }
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> Result<()> {
-    env_logger::init();
-    let mut ctx = Ctx {
-        dev: Device::new(Model::detect()?),
-        kb_charging: false,
-        last_step: Instant::now(),
-        last_offline: Instant::now(),
-    };
+// drive the control loop one tick per row until the scenario runs out
+async fn run_replay<B: BatterySink + Sync>(mut ctx: Ctx<B>) -> Result<()> {
     loop {
         time::sleep(Duration::from_secs(1)).await;
+        match ctx.step().await {
+            Ok(()) => (),
+            Err(e) => {
+                info!("replay finished: {}", e);
+                return Ok(());
+            }
+        }
+    }
+}
+
+// how often to poll as a safety net for power-supply attributes we
+// aren't watching (or whose watch failed to set up)
+const FALLBACK_POLL: Duration = Duration::from_secs(10);
+
+// sysfs power-supply attributes are updated by the kernel via
+// sysfs_notify(), which wakes up pollers blocked on POLLPRI -- inotify
+// does not see these changes, since nothing ever opens/writes the
+// file from userspace. Watch each attribute with POLLPRI via epoll
+// (through tokio's AsyncFd) instead, the same mechanism a C power
+// daemon would use with poll(2). Each watched attribute gets its own
+// task forwarding a wakeup over `tx` so `run_live` can select across
+// however many paths `watch_paths` returns.
+fn spawn_attr_watcher(path: PathBuf, tx: mpsc::Sender<()>) {
+    tokio::task::spawn(async move {
+        let file = match std::fs::File::open(&path) {
+            Ok(file) => file,
+            Err(e) => {
+                warn!("failed to open {} for watching: {}", path.display(), e);
+                return;
+            }
+        };
+        let afd = match AsyncFd::with_interest(file, Interest::PRIORITY) {
+            Ok(afd) => afd,
+            Err(e) => {
+                warn!("failed to watch {}: {}", path.display(), e);
+                return;
+            }
+        };
+        loop {
+            match afd.ready(Interest::PRIORITY).await {
+                Ok(mut guard) => {
+                    guard.clear_ready();
+                    if tx.send(()).await.is_err() {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    warn!("error watching {}: {}", path.display(), e);
+                    return;
+                }
+            }
+        }
+    });
+}
+
+// drive the control loop from power-supply sysfs change
+// notifications instead of a fixed 1s poll, so we react to
+// plug/unplug immediately while staying idle (and lower-power)
+// otherwise. Falls back to a periodic poll as a safety net for
+// whichever attributes aren't watched.
+async fn run_live<B: BatterySink + Sync>(mut ctx: Ctx<B>) -> Result<()> {
+    let (tx, mut rx) = mpsc::channel(1);
+    for path in ctx.dev.watch_paths() {
+        spawn_attr_watcher(path, tx.clone());
+    }
+    drop(tx);
+    loop {
+        tokio::select! {
+            ev = rx.recv() => {
+                // every watcher task has exited (e.g. none of the
+                // attributes could be opened); fall back to a plain
+                // periodic poll instead of spinning on a closed
+                // channel that's immediately "ready" forever.
+                if ev.is_none() {
+                    time::sleep(FALLBACK_POLL).await;
+                }
+            }
+            _ = time::sleep(FALLBACK_POLL) => (),
+        }
         if let Err(e) = ctx.step().await {
             error!("error: {} will retry", e);
         }
     }
 }
+
+// default location for the config file, overridable with the
+// KBPWRD_CONFIG environment variable
+const DEFAULT_CONFIG_PATH: &str = "/etc/pinephone-kbpwrd.toml";
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<()> {
+    env_logger::init();
+    let config_path =
+        PathBuf::from(std::env::var("KBPWRD_CONFIG").unwrap_or_else(|_| DEFAULT_CONFIG_PATH.into()));
+    let config = Config::load(&config_path).await?;
+    let (status_tx, status_rx) = watch::channel::<Option<Status>>(None);
+    let socket_path =
+        PathBuf::from(std::env::var("KBPWRD_SOCKET").unwrap_or_else(|_| DEFAULT_SOCKET_PATH.into()));
+    tokio::task::spawn(async move {
+        if let Err(e) = run_status_server(socket_path, status_rx).await {
+            error!("status server exited: {}", e);
+        }
+    });
+    match std::env::var("KBPWRD_REPLAY") {
+        Ok(path) => {
+            let dev = ReplaySource::load(&PathBuf::from(path)).await?;
+            run_replay(Ctx::new(dev, config, status_tx)).await
+        }
+        Err(_) => {
+            let dev = Device::new(Model::detect()?);
+            run_live(Ctx::new(dev, config, status_tx)).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(timestamp_secs: u64, kb_state: State, mb_state: State, mb_soc: u32) -> ReplayRow {
+        ReplayRow {
+            timestamp_secs,
+            kb_state,
+            kb_soc: Some(50),
+            kb_voltage: 4_000_000,
+            kb_current: -100_000,
+            kb_limit: 500_000,
+            kb_enabled: true,
+            mb_state,
+            mb_soc,
+            mb_voltage: 4_000_000,
+            mb_current: -100_000,
+            mb_limit: 500_000,
+        }
+    }
+
+    // below the low soc threshold (default 30%) the control loop
+    // should push current into the main battery instead of continuing
+    // to drain it; above the threshold while the kb battery itself is
+    // discharging, it should step the main battery down
+    #[tokio::test]
+    async fn discharging_crosses_low_soc_threshold() {
+        let rows = vec![
+            row(0, State::Discharging, State::Charging, 35),
+            row(10, State::Discharging, State::Discharging, 25),
+        ];
+        let dev = ReplaySource::new(Model::PinePhonePro, rows);
+        let (status_tx, _status_rx) = watch::channel::<Option<Status>>(None);
+        let mut ctx = Ctx::new(dev, Config::default(), status_tx);
+
+        ctx.step().await.unwrap();
+        ctx.step().await.unwrap();
+
+        assert_eq!(
+            ctx.dev.actions(),
+            vec![Action::MaybeStepDown, Action::MaybeStepUp]
+        );
+    }
+}